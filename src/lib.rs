@@ -1,28 +1,82 @@
 #![no_std]
 
 extern crate embedded_hal as hal;
+#[cfg(feature = "smbus_pec")]
+extern crate smbus_pec;
 use hal::blocking::i2c;
 use hal::blocking::delay;
+use hal::digital::v2::OutputPin;
+#[cfg(feature = "fmt")]
 use core::fmt;
+use core::marker::PhantomData;
+
+const SLEEP_COMMAND: u8 = 0xFF;
+
+/// Register map and default I2C address for a specific IC in the MLX9061x family.
+pub trait Ic {
+    const DEFAULT_ADDRESS: u8;
+    /// Minimum time SCL must be held low to wake the device from sleep, in milliseconds.
+    const WAKE_DELAY_MS: u8;
+    const RAM_TA: u8;
+    const RAM_TOBJ1: u8;
+    const RAM_RAW_IR1: u8;
+    const RAM_RAW_IR2: u8;
+    const EEPROM_TOMAX: u8;
+    const EEPROM_TOMIN: u8;
+    const EEPROM_PWMCTRL: u8;
+//    const EEPROM_TARANGE: u8;
+//    const EEPROM_KE: u8;
+    const EEPROM_CONFIG: u8;
+    const EEPROM_ADDRESS: u8;
+    const EEPROM_EMISSIVITY: u8;
+    const EEPROM_ID0: u8;
+    const EEPROM_ID1: u8;
+    const EEPROM_ID2: u8;
+    const EEPROM_ID3: u8;
+}
+
+/// IC marker for the MLX90614, the default IC variant.
+pub struct Ic90614;
+
+/// IC marker for the single-zone MLX90615.
+pub struct Ic90615;
+
+impl Ic for Ic90614 {
+    const DEFAULT_ADDRESS: u8 = 0x5A;
+    const WAKE_DELAY_MS: u8 = 33;
+    const RAM_TA: u8 = 0x06;
+    const RAM_TOBJ1: u8 = 0x07;
+    const RAM_RAW_IR1: u8 = 0x04;
+    const RAM_RAW_IR2: u8 = 0x05;
+    const EEPROM_TOMAX: u8 = 0x20;
+    const EEPROM_TOMIN: u8 = 0x21;
+    const EEPROM_PWMCTRL: u8 = 0x22;
+    const EEPROM_CONFIG: u8 = 0x25;
+    const EEPROM_ADDRESS: u8 = 0x2E;
+    const EEPROM_EMISSIVITY: u8 = 0x04 | 0x20;
+    const EEPROM_ID0: u8 = 0x3C;
+    const EEPROM_ID1: u8 = 0x3D;
+    const EEPROM_ID2: u8 = 0x3E;
+    const EEPROM_ID3: u8 = 0x3F;
+}
 
-#[derive(Copy, Clone)]
-#[repr(u8)]
-enum Register {
-    TA = 0x06,
-    TOBJ1 = 0x07,
-    TOBJ2 = 0x08,
-    TOMAX = 0x20,
-    TOMIN = 0x21,
-//    PWMCTRL = 0x22,
-//    TARANGE = 0x23,
-//    KE = 0x24,
-//    CONFIG = 0x25,
-    ADDRESS = 0x2E,
-    ID0 = 0x3C,
-    ID1 = 0x3D,
-    ID2 = 0x3E,
-    ID3 = 0x3F,
-//   SLEEP = 0xFF,
+impl Ic for Ic90615 {
+    const DEFAULT_ADDRESS: u8 = 0x5B;
+    const WAKE_DELAY_MS: u8 = 39;
+    const RAM_TA: u8 = 0x06 | 0x20;
+    const RAM_TOBJ1: u8 = 0x07 | 0x20;
+    const RAM_RAW_IR1: u8 = 0x04 | 0x20;
+    const RAM_RAW_IR2: u8 = 0x05 | 0x20;
+    const EEPROM_TOMAX: u8 = 0x20 | 0x10;
+    const EEPROM_TOMIN: u8 = 0x21 | 0x10;
+    const EEPROM_PWMCTRL: u8 = 0x22 | 0x10;
+    const EEPROM_CONFIG: u8 = 0x25 | 0x10;
+    const EEPROM_ADDRESS: u8 = 0x2E | 0x10;
+    const EEPROM_EMISSIVITY: u8 = (0x04 | 0x20) | 0x10;
+    const EEPROM_ID0: u8 = 0x3C | 0x10;
+    const EEPROM_ID1: u8 = 0x3D | 0x10;
+    const EEPROM_ID2: u8 = 0x3E | 0x10;
+    const EEPROM_ID3: u8 = 0x3F | 0x10;
 }
 
 #[derive(Copy, Clone, PartialEq)]
@@ -106,12 +160,139 @@ impl Temperature {
     }
 }
 
+/// Digital filter, gain, and sensor-mode settings parsed from the `CONFIG` EEPROM word.
+#[derive(Copy, Clone, PartialEq)]
+#[cfg_attr(feature = "fmt", derive(Debug))]
+pub struct Config {
+    /// IIR filter coefficient, `0..=7`.
+    pub iir: u8,
+    /// FIR filter coefficient, `0..=7`.
+    pub fir: u8,
+    /// Amplifier gain setting, `0..=7`.
+    pub gain: u8,
+    /// `true` selects dual IR sensor mode.
+    pub dual_ir_sensor: bool,
+    /// Sign of the Kt2 compensation coefficient: `true` for negative.
+    pub kt2_sign_negative: bool,
+}
+
+impl Config {
+    /// Bits of the CONFIG word that `Config` models; the remaining bits are reserved
+    /// by the datasheet and must keep their factory value, so writes preserve them.
+    const KNOWN_BITS: u16 = 0x07 | (0x07 << 8) | (0x07 << 11) | (1 << 14) | (1 << 15);
+
+    fn from_raw(raw: u16) -> Self {
+        Config {
+            iir: (raw & 0x07) as u8,
+            fir: ((raw >> 8) & 0x07) as u8,
+            gain: ((raw >> 11) & 0x07) as u8,
+            dual_ir_sensor: raw & (1 << 14) == 0,
+            kt2_sign_negative: raw & (1 << 15) != 0,
+        }
+    }
+
+    /// Builds the CONFIG word to write, preserving every bit of `current` that
+    /// `Config` doesn't model.
+    fn into_raw(self, current: u16) -> u16 {
+        let mut raw = current & !Self::KNOWN_BITS;
+
+        raw |= (self.iir as u16 & 0x07) | ((self.fir as u16 & 0x07) << 8) | ((self.gain as u16 & 0x07) << 11);
+
+        if !self.dual_ir_sensor {
+            raw |= 1 << 14;
+        }
+        if self.kt2_sign_negative {
+            raw |= 1 << 15;
+        }
+
+        raw
+    }
+}
+
+/// Output mode for the PWM/SDA pin, configured via `PWMCTRL`.
+#[derive(Copy, Clone, PartialEq)]
+#[cfg_attr(feature = "fmt", derive(Debug))]
+pub enum PwmMode {
+    PwmOutput,
+    ThermalRelay,
+    ExtendedRange,
+}
+
+/// Selects which temperature the PWM output tracks.
+#[derive(Copy, Clone, PartialEq)]
+#[cfg_attr(feature = "fmt", derive(Debug))]
+pub enum PwmSource {
+    Object,
+    Ambient,
+}
+
+/// Configuration for the PWM/SDA pin, parsed from the `PWMCTRL` EEPROM word.
+#[derive(Copy, Clone, PartialEq)]
+#[cfg_attr(feature = "fmt", derive(Debug))]
+pub struct PwmConfig {
+    pub enabled: bool,
+    pub mode: PwmMode,
+    pub source: PwmSource,
+    /// PWM period in units of 1.024 ms, `0..=0x7F`.
+    pub period: u8,
+    /// Number of periods the PWM repeats before returning to idle, `0..=3`.
+    pub repetitions: u8,
+}
+
+impl PwmConfig {
+    /// Bits of the PWMCTRL word that `PwmConfig` models; the remaining bits are left
+    /// untouched by writes.
+    const KNOWN_BITS: u16 = 0x01 | (0x03 << 1) | (1 << 3) | (0x7F << 4) | (0x03 << 11);
+
+    fn from_raw(raw: u16) -> Self {
+        let mode = match (raw >> 1) & 0x03 {
+            0 => PwmMode::PwmOutput,
+            1 => PwmMode::ThermalRelay,
+            _ => PwmMode::ExtendedRange,
+        };
+
+        PwmConfig {
+            enabled: raw & 0x01 != 0,
+            mode,
+            source: if raw & (1 << 3) != 0 { PwmSource::Ambient } else { PwmSource::Object },
+            period: ((raw >> 4) & 0x7F) as u8,
+            repetitions: ((raw >> 11) & 0x03) as u8,
+        }
+    }
+
+    /// Builds the PWMCTRL word to write, preserving every bit of `current` that
+    /// `PwmConfig` doesn't model.
+    fn into_raw(self, current: u16) -> u16 {
+        let mode_bits: u16 = match self.mode {
+            PwmMode::PwmOutput => 0,
+            PwmMode::ThermalRelay => 1,
+            PwmMode::ExtendedRange => 2,
+        };
+
+        let mut raw = current & !Self::KNOWN_BITS;
+
+        raw |= (mode_bits << 1)
+            | ((self.period as u16 & 0x7F) << 4)
+            | ((self.repetitions as u16 & 0x03) << 11);
+
+        if self.enabled {
+            raw |= 0x01;
+        }
+        if self.source == PwmSource::Ambient {
+            raw |= 1 << 3;
+        }
+
+        raw
+    }
+}
+
 #[cfg_attr(feature = "fmt", derive(Debug))]
 pub enum Error<I2CError> {
     I2C(I2CError),
     Flag,
     Crc,
     InvalidAddress(u8),
+    InvalidEmissivity(f32),
 }
 
 impl<I2CError> From<I2CError> for Error<I2CError> {
@@ -120,39 +301,44 @@ impl<I2CError> From<I2CError> for Error<I2CError> {
     }
 }
 
-pub struct Mlx90614<I2C, Delay> {
+#[cfg_attr(feature = "fmt", derive(Debug))]
+pub enum PinError<I2CError, E> {
+    I2C(I2CError),
+    Pin(E),
+}
+
+impl<I2CError, E> From<I2CError> for PinError<I2CError, E> {
+    fn from(item: I2CError) -> Self {
+        PinError::I2C(item)
+    }
+}
+
+pub struct Mlx90614<I2C, Delay, IC = Ic90614> {
     i2c: I2C,
     address: u8,
     delay: Delay,
+    _ic: PhantomData<IC>,
 }
 
 pub const DEFAULT_ADDRESS: u8 = 0x5A;
 
-impl<I2C, I2CError, Delay> Mlx90614<I2C, Delay> 
+impl<I2C, I2CError, Delay, IC> Mlx90614<I2C, Delay, IC>
 where
     I2C: i2c::WriteRead<Error = I2CError> + i2c::Read<Error = I2CError> + i2c::Write<Error = I2CError>,
     Delay: delay::DelayMs<u8>,
+    IC: Ic,
 {
     pub fn new(i2c: I2C, delay: Delay) -> Result<Self, Error<I2CError>> {
-        Self::with_address(i2c, DEFAULT_ADDRESS, delay)
+        Self::with_address(i2c, IC::DEFAULT_ADDRESS, delay)
     }
 
     pub fn with_address(i2c: I2C, address: u8, delay: Delay) -> Result<Self, Error<I2CError>> {
         validate_address(address)?;
-        Ok(Mlx90614 { i2c, address, delay })
+        Ok(Mlx90614 { i2c, address, delay, _ic: PhantomData })
     }
 
     pub fn read_object(&mut self) -> Result<Temperature, Error<I2CError>> {
-        let raw = self.i2c_read(Register::TOBJ1)?;
-        if raw & 0x8000 != 0 {
-            Err(Error::Flag)
-        } else {
-            Ok(Temperature::Raw(raw as i16))
-        }
-    }
-
-    pub fn read_object2(&mut self) -> Result<Temperature, Error<I2CError>> {
-        let raw = self.i2c_read(Register::TOBJ2)?;
+        let raw = self.i2c_read(IC::RAM_TOBJ1)?;
         if raw & 0x8000 != 0 {
             Err(Error::Flag)
         } else {
@@ -161,116 +347,246 @@ where
     }
 
     pub fn read_ambient(&mut self) -> Result<Temperature, Error<I2CError>> {
-        let raw = self.i2c_read(Register::TA)?;
+        let raw = self.i2c_read(IC::RAM_TA)?;
         Ok(Temperature::Raw(raw as i16))
     }
 
     pub fn get_min(&mut self) -> Result<Temperature, Error<I2CError>> {
-        let raw = self.i2c_read(Register::TOMIN)?;
+        let raw = self.i2c_read(IC::EEPROM_TOMIN)?;
         Ok(Temperature::Raw(raw as i16))
     }
 
     pub fn get_max(&mut self) -> Result<Temperature, Error<I2CError>> {
-        let raw = self.i2c_read(Register::TOMAX)?;
+        let raw = self.i2c_read(IC::EEPROM_TOMAX)?;
         Ok(Temperature::Raw(raw as i16))
     }
 
     pub fn set_min(&mut self, min: Temperature) -> Result<(), I2CError> {
-        self.eeprom_write(Register::TOMIN, min.into_raw() as u16)
+        self.eeprom_write(IC::EEPROM_TOMIN, min.into_raw() as u16)
     }
 
     pub fn set_max(&mut self, max: Temperature) -> Result<(), I2CError> {
-        self.eeprom_write(Register::TOMAX, max.into_raw() as u16)
+        self.eeprom_write(IC::EEPROM_TOMAX, max.into_raw() as u16)
+    }
+
+    pub fn get_emissivity(&mut self) -> Result<f32, Error<I2CError>> {
+        let raw = self.i2c_read(IC::EEPROM_EMISSIVITY)?;
+        Ok(raw as f32 / 65535.0)
     }
 
-    // pub fn sleep(&mut self) -> Result<(), Error<I2CError>> {
-    //     let crc = crc8(&[self.address << 1, Register::SLEEP as u8]);
+    pub fn set_emissivity(&mut self, emissivity: f32) -> Result<(), Error<I2CError>> {
+        if !(0.1..=1.0).contains(&emissivity) {
+            return Err(Error::InvalidEmissivity(emissivity));
+        }
 
-    //     self.i2c.write(self.address, &[Register::SLEEP as u8, crc])?;
+        let raw = (emissivity * 65535.0) as u16;
+        self.eeprom_write(IC::EEPROM_EMISSIVITY, raw)?;
 
-    //     // TODO: pull SCL low
+        Ok(())
+    }
 
-    //     Ok(())
-    // }
+    pub fn read_raw_ir1(&mut self) -> Result<i16, Error<I2CError>> {
+        let raw = self.i2c_read(IC::RAM_RAW_IR1)?;
+        Ok(raw as i16)
+    }
 
-    // pub fn wake(&mut self) -> Result<(), Error<I2CError>> {
-    //     // TODO: manual control of pin writes while having i2c peripheral?
-    //     Ok(())
-    // }
+    pub fn read_raw_ir2(&mut self) -> Result<i16, Error<I2CError>> {
+        let raw = self.i2c_read(IC::RAM_RAW_IR2)?;
+        Ok(raw as i16)
+    }
+
+    pub fn get_config(&mut self) -> Result<Config, Error<I2CError>> {
+        let raw = self.i2c_read(IC::EEPROM_CONFIG)?;
+        Ok(Config::from_raw(raw))
+    }
+
+    pub fn set_config(&mut self, config: Config) -> Result<(), Error<I2CError>> {
+        let current = self.i2c_read(IC::EEPROM_CONFIG)?;
+        self.eeprom_write(IC::EEPROM_CONFIG, config.into_raw(current))?;
+        Ok(())
+    }
+
+    pub fn get_pwm_config(&mut self) -> Result<PwmConfig, Error<I2CError>> {
+        let raw = self.i2c_read(IC::EEPROM_PWMCTRL)?;
+        Ok(PwmConfig::from_raw(raw))
+    }
+
+    pub fn set_pwm_config(&mut self, config: PwmConfig) -> Result<(), Error<I2CError>> {
+        let current = self.i2c_read(IC::EEPROM_PWMCTRL)?;
+        self.eeprom_write(IC::EEPROM_PWMCTRL, config.into_raw(current))?;
+        Ok(())
+    }
+
+    /// Takes over `scl`/`sda` directly to hold the device in sleep; call [`Mlx90614::wake`]
+    /// before reading again.
+    pub fn sleep<SCL, SDA>(
+        &mut self,
+        scl: &mut SCL,
+        sda: &mut SDA,
+    ) -> Result<(), PinError<I2CError, SCL::Error>>
+    where
+        SCL: OutputPin,
+        SDA: OutputPin<Error = SCL::Error>,
+    {
+        let crc = crc8(&[self.address << 1, SLEEP_COMMAND]);
+
+        self.i2c.write(self.address, &[SLEEP_COMMAND, crc])?;
+
+        sda.set_high().map_err(PinError::Pin)?;
+        scl.set_low().map_err(PinError::Pin)?;
+
+        Ok(())
+    }
+
+    /// RAM reads are invalid until a full measurement cycle completes after waking.
+    pub fn wake<SCL, SDA>(
+        &mut self,
+        scl: &mut SCL,
+        sda: &mut SDA,
+    ) -> Result<(), PinError<I2CError, SCL::Error>>
+    where
+        SCL: OutputPin,
+        SDA: OutputPin<Error = SCL::Error>,
+    {
+        // release the bus
+        scl.set_high().map_err(PinError::Pin)?;
+        sda.set_high().map_err(PinError::Pin)?;
+
+        // hold SCL low for the wake delay
+        scl.set_low().map_err(PinError::Pin)?;
+        self.delay.delay_ms(IC::WAKE_DELAY_MS);
+
+        // release SCL, leave SDA high, and let the device settle before the next read
+        scl.set_high().map_err(PinError::Pin)?;
+        sda.set_high().map_err(PinError::Pin)?;
+        self.delay.delay_ms(IC::WAKE_DELAY_MS);
+
+        Ok(())
+    }
 
     pub fn get_address(&mut self) -> Result<u8, Error<I2CError>> {
-        self.i2c_read(Register::ADDRESS).map(|v| v as u8)
+        self.i2c_read(IC::EEPROM_ADDRESS).map(|v| v as u8)
     }
 
     pub fn set_address(&mut self, address: u8) -> Result<(), Error<I2CError>> {
         validate_address(address)?;
 
         // We're only supposted to modify the lsbyte
-        let mut address_value = self.i2c_read(Register::ADDRESS)?;        
+        let mut address_value = self.i2c_read(IC::EEPROM_ADDRESS)?;
 
         address_value &= 0xFF00;
         address_value |= address as u16;
 
-        self.eeprom_write(Register::ADDRESS, address_value)?;
+        self.eeprom_write(IC::EEPROM_ADDRESS, address_value)?;
 
         Ok(())
     }
 
     pub fn get_id(&mut self) -> Result<u64, Error<I2CError>> {
-        let id0 = self.i2c_read(Register::ID0)?;
-        let id1 = self.i2c_read(Register::ID1)?;
-        let id2 = self.i2c_read(Register::ID2)?;
-        let id3 = self.i2c_read(Register::ID3)?;
+        let id0 = self.i2c_read(IC::EEPROM_ID0)?;
+        let id1 = self.i2c_read(IC::EEPROM_ID1)?;
+        let id2 = self.i2c_read(IC::EEPROM_ID2)?;
+        let id3 = self.i2c_read(IC::EEPROM_ID3)?;
         Ok((id3 as u64) << 48 | (id2 as u64) << 32 | (id1 as u64) << 16 | id0 as u64)
     }
 
-    fn i2c_read(&mut self, reg: Register) -> Result<u16, Error<I2CError>> {
-        let reg = reg as  u8;
+    fn i2c_read(&mut self, reg: u8) -> Result<u16, Error<I2CError>> {
         let mut data = [0u8; 3];
         self.i2c.write_read(self.address, &[reg], &mut data)?;
 
         let lsb = data[0];
         let msb = data[1];
         let pec = data[2];
-        
+
         let crc = crc8(&[self.address << 1, reg, (self.address << 1) + 1, lsb, msb]);
 
-        if crc != pec { 
+        if crc != pec {
             return Err(Error::Crc);
         }
 
         Ok(((msb as u16) << 8) | lsb as u16)
     }
 
-    fn i2c_write(&mut self, reg: Register, data: u16) -> Result<(), I2CError> {
-        let reg = reg as u8;
-        let lsb = (data & 0xFF) as u8;
-        let msb = (data >> 8) as u8;
+    fn eeprom_write(&mut self, reg: u8, data: u16) -> Result<(), I2CError> {
+        Self::eeprom_write_impl(&mut self.i2c, self.address, reg, data, 5, &mut self.delay)
+    }
 
-        let crc = crc8(&[self.address << 1, reg, lsb, msb]);
+    /// Like the EEPROM write helpers used by `set_min`/`set_max`/etc, but takes the
+    /// inter-write delay as a parameter instead of forcing the driver's fixed 5 ms
+    /// blocking wait, so callers can choose a different delay or `DelayMs` implementation.
+    /// Both waits still run inside this call through `delay.delay_ms`: it returns only
+    /// after the erase, the write and both delays have completed.
+    pub fn eeprom_write_with_delay<D>(
+        &mut self,
+        reg: u8,
+        data: u16,
+        delay_ms: u8,
+        delay: &mut D,
+    ) -> Result<(), I2CError>
+    where
+        D: delay::DelayMs<u8>,
+    {
+        Self::eeprom_write_impl(&mut self.i2c, self.address, reg, data, delay_ms, delay)
+    }
 
-        self.i2c.write(self.address, &[reg, lsb, msb, crc])?;
+    /// Shared body of `eeprom_write`/`eeprom_write_with_delay`: erase the EEPROM
+    /// register, then write the new value, waiting `delay_ms` after each I2C write
+    /// for the device to finish committing it.
+    fn eeprom_write_impl<D>(
+        i2c: &mut I2C,
+        address: u8,
+        reg: u8,
+        data: u16,
+        delay_ms: u8,
+        delay: &mut D,
+    ) -> Result<(), I2CError>
+    where
+        D: delay::DelayMs<u8>,
+    {
+        // zero out EEPROM register
+        Self::i2c_write_raw(i2c, address, reg, 0)?;
+        delay.delay_ms(delay_ms);
+
+        // write data
+        Self::i2c_write_raw(i2c, address, reg, data)?;
+        delay.delay_ms(delay_ms);
 
         Ok(())
     }
 
-    fn eeprom_write(&mut self, reg: Register, data: u16) -> Result<(), I2CError> {
-        // zero out EEPROM register
-        self.i2c_write(reg, 0)?;
+    fn i2c_write_raw(i2c: &mut I2C, address: u8, reg: u8, data: u16) -> Result<(), I2CError> {
+        let lsb = (data & 0xFF) as u8;
+        let msb = (data >> 8) as u8;
 
-        // wait for write to complete
-        self.delay.delay_ms(5);
-        
-        // write data
-        self.i2c_write(reg, data)?;
+        let crc = crc8(&[address << 1, reg, lsb, msb]);
 
-        // wait for write to complete
-        self.delay.delay_ms(5);
+        i2c.write(address, &[reg, lsb, msb, crc])?;
 
         Ok(())
     }
 }
 
+impl<I2C, I2CError, Delay> Mlx90614<I2C, Delay, Ic90614>
+where
+    I2C: i2c::WriteRead<Error = I2CError> + i2c::Read<Error = I2CError> + i2c::Write<Error = I2CError>,
+    Delay: delay::DelayMs<u8>,
+{
+    pub fn read_object2(&mut self) -> Result<Temperature, Error<I2CError>> {
+        const TOBJ2: u8 = 0x08;
+
+        let raw = self.i2c_read(TOBJ2)?;
+        if raw & 0x8000 != 0 {
+            Err(Error::Flag)
+        } else {
+            Ok(Temperature::Raw(raw as i16))
+        }
+    }
+}
+
+/// Computes the SMBus Packet Error Code for `data` using the bit-banged polynomial
+/// division. Enable the `smbus_pec` feature to use the `smbus_pec` crate's table-driven
+/// implementation instead, which avoids the per-byte bit loop on hot paths.
+#[cfg(not(feature = "smbus_pec"))]
 fn crc8(data: &[u8]) -> u8 {
     let mut crc = 0u8;
     for byte in data {
@@ -287,6 +603,11 @@ fn crc8(data: &[u8]) -> u8 {
     crc
 }
 
+#[cfg(feature = "smbus_pec")]
+fn crc8(data: &[u8]) -> u8 {
+    smbus_pec::pec(data)
+}
+
 /// Returns an error if the given address is not a valid I2C address.
 fn validate_address<T>(address: u8) -> Result<(), Error<T>> {
     if address > 0x80 || address == 0 {